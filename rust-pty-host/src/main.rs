@@ -1,229 +1,74 @@
+mod proto;
+mod recorder;
+mod replay;
+
 use clap::Parser;
 use eyre::eyre;
-use portable_pty::{CommandBuilder, PtySize, native_pty_system};
-use s2::batching::{AppendRecordsBatchingOpts, AppendRecordsBatchingStream};
-use s2::client::S2Endpoints;
-use s2::types::{
-    AppendRecord, BasinName, Header, ReadOutput, ReadSessionRequest, ReadStart, SequencedRecord,
-    StreamPosition,
-};
-use s2::{ClientConfig, StreamClient};
-use std::io::Read;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
-use tokio_stream::StreamExt;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tracing::{error, trace};
+use recorder::{BatchOpts, ReconnectOpts, SnapshotOpts};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     basin: String,
     session: String,
+    /// Command to spawn in the PTY. Required unless `--replay` is set.
     #[arg(long)]
-    process: String,
-}
-
-enum Input {
-    Keystroke(Vec<u8>),
-    WindowResize { rows: u16, cols: u16 },
-}
-
-impl TryFrom<SequencedRecord> for Input {
-    type Error = eyre::Report;
-
-    fn try_from(value: SequencedRecord) -> Result<Self, Self::Error> {
-        let type_header = value.headers.first().ok_or(eyre!("no headers"))?;
-        if type_header.name != "type" {
-            return Err(eyre!("first header does not contain type"));
-        }
-        let type_value = String::from_utf8(type_header.value.to_vec())?;
-        match type_value.as_ref() {
-            "keystroke" => Ok(Input::Keystroke(value.body.to_vec())),
-            "window" => {
-                let Header {
-                    name,
-                    value: header_value,
-                } = value.headers.get(1).ok_or(eyre!("missing rows header"))?;
-                let rows = if name == "rows" {
-                    String::from_utf8(header_value.to_vec())?
-                        .as_str()
-                        .parse::<u16>()?
-                } else {
-                    return Err(eyre!("missing rows value"));
-                };
-                let Header {
-                    name,
-                    value: header_value,
-                } = value.headers.get(2).ok_or(eyre!("missing cols header"))?;
-                let cols = if name == "cols" {
-                    String::from_utf8(header_value.to_vec())?
-                        .as_str()
-                        .parse::<u16>()?
-                } else {
-                    return Err(eyre!("missing cols value"));
-                };
-                Ok(Input::WindowResize { rows, cols })
-            }
-            _ => Err(eyre!("unrecognized type")),
-        }
-    }
-}
-
-/// Get current timestamp in ms.
-fn timestamp_now() -> u64 {
-    let now = SystemTime::now();
-    now.duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis() as u64
+    process: Option<String>,
+    /// Replay a recorded session instead of recording a live one.
+    #[arg(long)]
+    replay: bool,
+    /// Seq num in `term_output` to start the replay from. If omitted, replay
+    /// fast-starts from the most recent `snapshot` record near the tail.
+    #[arg(long)]
+    start_seq_num: Option<u64>,
+    /// Playback speed multiplier; e.g. 2.0 plays twice as fast, 0.5 half as fast.
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Emit a screen snapshot after this many `out` records since the last one.
+    #[arg(long, default_value_t = 500)]
+    snapshot_every_records: u32,
+    /// Emit a screen snapshot after this many seconds since the last one.
+    #[arg(long, default_value_t = 30)]
+    snapshot_every_secs: u64,
+    /// Initial backoff (ms) before reconnecting a dropped `term_input`/`term_output` stream.
+    #[arg(long, default_value_t = 200)]
+    reconnect_min_backoff_ms: u64,
+    /// Maximum backoff (ms) between reconnect attempts, after repeated failures.
+    #[arg(long, default_value_t = 10_000)]
+    reconnect_max_backoff_ms: u64,
+    /// Maximum number of records to coalesce into one append batch.
+    #[arg(long, default_value_t = 1000)]
+    max_batch_records: u32,
+    /// How long to linger (ms) for more records before flushing an append batch.
+    #[arg(long, default_value_t = 0)]
+    batch_linger_ms: u64,
 }
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     tracing_subscriber::fmt::init();
-    let pty_system = native_pty_system();
 
     let args = Args::parse();
 
-    let pair = pty_system
-        .openpty(PtySize {
-            rows: 32,
-            cols: 72,
-            pixel_width: 0,
-            pixel_height: 0,
-        })
-        .map_err(|e| eyre!(e))?;
-
-    let cmd = CommandBuilder::new(args.process);
-    let _child = pair.slave.spawn_command(cmd).map_err(|e| eyre!(e))?;
-
-    // Obtain a reader and writer for the PTY master
-    let reader = pair.master.try_clone_reader().map_err(|e| eyre!(e))?;
-    let mut writer = pair.master.take_writer().map_err(|e| eyre!(e))?;
-
-    let input_stream = format!("sessions/{}/term_input", args.session);
-    let output_stream = format!("sessions/{}/term_output", args.session);
-
-    // Note that we always start from the current tail of the input stream.
-    let mut keystrokes = StreamClient::new(
-        ClientConfig::new(std::env::var("S2_ACCESS_TOKEN")?)
-            .with_endpoints(S2Endpoints::from_env().map_err(|msg| eyre!(msg))?),
-        BasinName::try_from(args.basin.clone())?,
-        input_stream,
-    )
-    .read_session(ReadSessionRequest::new(ReadStart::TailOffset(0)))
-    .await?;
-
-    let (append_tx, append_rx) = mpsc::unbounded_channel();
-
-    let output_client = StreamClient::new(
-        ClientConfig::new(std::env::var("S2_ACCESS_TOKEN")?)
-            .with_endpoints(S2Endpoints::from_env().map_err(|msg| eyre!(msg))?),
-        BasinName::try_from(args.basin)?,
-        output_stream,
-    );
-
-    // Get the current tail of the output stream, and use that for `match_seq_num`.
-    let StreamPosition { seq_num, .. } = output_client.check_tail().await?;
-
-    // Configure a batching stream.
-    // This is not strictly necessary, but `with_match_seq_num` gives us protection against duplicates.
-    let batching_opts = AppendRecordsBatchingOpts::new()
-        .with_max_batch_records(1000)
-        .with_linger(Duration::from_millis(0))
-        .with_match_seq_num(Some(seq_num));
-
-    let mut append = output_client
-        .append_session(AppendRecordsBatchingStream::new(
-            UnboundedReceiverStream::new(append_rx),
-            batching_opts,
-        ))
-        .await?;
-
-    // Spawn a task to consume from the PTY reader.
-    // The `read` fn blocks, so this needs to happen in its own task.
-    // Whenever a read finishes, we communicate it via mpsc channel.
-    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
-    tokio::task::spawn_blocking(move || {
-        let mut reader = reader;
-        let mut buf = [0u8; 1024 * 10];
-        loop {
-            match reader.read(&mut buf) {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    let data = buf[..n].to_vec();
-                    if tx.send(data).is_err() {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!(?e, "read error");
-                    break;
-                }
-            }
-        }
-    });
-
-    'outer: loop {
-        tokio::select! {
-
-            // Handle messages from the input stream.
-            Some(msg) = keystrokes.next() => {
-                let msg = msg?;
-                if let ReadOutput::Batch(batch) = msg {
-                    for record in batch.records {
-                        match Input::try_from(record)? {
-                            Input::Keystroke(key) => {
-                                trace!(?key, "keystroke");
-                                let write: Result<(), eyre::Report> = (|| {
-                                    writer.write_all(key.as_slice())?;
-                                    writer.flush()?;
-                                    Ok(())
-                                })();
-                                if let Err(e) = write {
-                                    error!(?e);
-                                    break 'outer;
-                                }
-                            },
-                            Input::WindowResize { rows, cols } => {
-                                trace!(?rows, ?cols, "window resize");
-                                pair.master.resize(PtySize {
-                                    rows,
-                                    cols,
-                                    pixel_width: 0,
-                                    pixel_height: 0
-                                }).map_err(|e| eyre!(e))?;
-                            }
-                        }
-                    }
-                }
-            }
-
-            // Handle PTY output.
-            Some(msg) = rx.recv() => {
-                let content = String::from_utf8_lossy(&msg);
-                trace!(?content);
-                let record = AppendRecord::new(msg)?.with_timestamp(timestamp_now()).with_headers([Header::new("type", "out")])?;
-                append_tx.send(record)?;
-            }
-
-            // Acknowledgements from appends to the output stream.
-            Some(ack) = append.next() => {
-                let ack = ack?;
-                trace!(?ack);
-            }
-
-            else => {
-                break;
-            }
-        }
+    if args.replay {
+        return replay::run(args.basin, args.session, args.start_seq_num, args.speed).await;
     }
 
-    append_tx.send(
-        AppendRecord::new("\r\n\x1b[31mserver crashed :-!\x1b[0m\r")?
-            .with_timestamp(timestamp_now())
-            .with_headers([Header::new("type", "out")])?,
-    )?;
-
-    Ok(())
+    let process = args
+        .process
+        .ok_or_else(|| eyre!("--process is required unless --replay is set"))?;
+    let snapshots = SnapshotOpts {
+        every_records: args.snapshot_every_records,
+        every: Duration::from_secs(args.snapshot_every_secs),
+    };
+    let reconnect = ReconnectOpts {
+        min_backoff: Duration::from_millis(args.reconnect_min_backoff_ms),
+        max_backoff: Duration::from_millis(args.reconnect_max_backoff_ms),
+    };
+    let batch = BatchOpts {
+        max_batch_records: args.max_batch_records,
+        linger: Duration::from_millis(args.batch_linger_ms),
+    };
+    recorder::run(args.basin, args.session, process, snapshots, reconnect, batch).await
 }
@@ -0,0 +1,171 @@
+use eyre::eyre;
+use s2::types::{Header, SequencedRecord};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn find_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a Header> {
+    headers.iter().find(|h| h.name == name)
+}
+
+fn header_str(headers: &[Header], name: &str) -> eyre::Result<String> {
+    let Header { value, .. } = find_header(headers, name).ok_or(eyre!("missing {name} header"))?;
+    Ok(String::from_utf8(value.to_vec())?)
+}
+
+fn header_u16(headers: &[Header], name: &str) -> eyre::Result<u16> {
+    Ok(header_str(headers, name)?.parse()?)
+}
+
+fn header_u64(headers: &[Header], name: &str) -> eyre::Result<u64> {
+    Ok(header_str(headers, name)?.parse()?)
+}
+
+/// Whether a `term_input` client may drive the session or only observe it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    ReadWrite,
+    ReadOnly,
+}
+
+impl Role {
+    fn parse(s: &str) -> eyre::Result<Role> {
+        match s {
+            "rw" => Ok(Role::ReadWrite),
+            "ro" => Ok(Role::ReadOnly),
+            other => Err(eyre!("unrecognized role {other}")),
+        }
+    }
+}
+
+/// A parsed record from `term_input`: either a keystroke to forward to the
+/// PTY, or a resize of its window. Every record must name the `client` it
+/// came from; an absent `role` header defaults to read-write, so existing
+/// callers that predate the `role` header keep working unchanged.
+pub enum Input {
+    Keystroke {
+        client: String,
+        role: Role,
+        data: Vec<u8>,
+    },
+    WindowResize {
+        client: String,
+        role: Role,
+        rows: u16,
+        cols: u16,
+    },
+}
+
+impl TryFrom<SequencedRecord> for Input {
+    type Error = eyre::Report;
+
+    fn try_from(value: SequencedRecord) -> Result<Self, Self::Error> {
+        let type_value = header_str(&value.headers, "type")?;
+        let client = header_str(&value.headers, "client")?;
+        let role = match find_header(&value.headers, "role") {
+            Some(header) => Role::parse(&String::from_utf8(header.value.to_vec())?)?,
+            None => Role::ReadWrite,
+        };
+        match type_value.as_str() {
+            "keystroke" => Ok(Input::Keystroke {
+                client,
+                role,
+                data: value.body.to_vec(),
+            }),
+            "window" => {
+                let rows = header_u16(&value.headers, "rows")?;
+                let cols = header_u16(&value.headers, "cols")?;
+                Ok(Input::WindowResize { client, role, rows, cols })
+            }
+            _ => Err(eyre!("unrecognized type")),
+        }
+    }
+}
+
+/// A parsed record from `term_output`: a chunk of raw PTY bytes, a resize
+/// event recorded alongside the output so a replay can reconstruct screen
+/// size changes at the point they happened, or a periodic snapshot of the
+/// reconstructed screen. `out`/`window` records carry the set of `client`
+/// ids attached at the time they were emitted, so downstream tooling can
+/// attribute activity in a shared session.
+pub enum Output {
+    Bytes {
+        clients: Vec<String>,
+        data: Vec<u8>,
+    },
+    WindowResize {
+        clients: Vec<String>,
+        rows: u16,
+        cols: u16,
+    },
+    /// A `vt100` screen dump covering every `out`/`window` record up to and
+    /// including `base_seq`. A reader can skip straight to `base_seq + 1`
+    /// after loading `body` instead of replaying from the start of the
+    /// stream.
+    Snapshot {
+        base_seq: u64,
+        rows: u16,
+        cols: u16,
+        body: Vec<u8>,
+    },
+    /// The recorded process exited; this is the last record on the stream.
+    Exit { code: u32, reason: String },
+}
+
+/// Parse the `clients` header as a comma-separated list of client ids.
+/// Missing (e.g. on records predating this feature) means none recorded.
+fn header_clients(headers: &[Header]) -> eyre::Result<Vec<String>> {
+    match find_header(headers, "clients") {
+        Some(header) => {
+            let joined = String::from_utf8(header.value.to_vec())?;
+            Ok(joined.split(',').filter(|s| !s.is_empty()).map(str::to_owned).collect())
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+impl TryFrom<SequencedRecord> for Output {
+    type Error = eyre::Report;
+
+    fn try_from(value: SequencedRecord) -> Result<Self, Self::Error> {
+        let type_value = header_str(&value.headers, "type")?;
+        match type_value.as_str() {
+            "out" => {
+                let clients = header_clients(&value.headers)?;
+                Ok(Output::Bytes {
+                    clients,
+                    data: value.body.to_vec(),
+                })
+            }
+            "window" => {
+                let rows = header_u16(&value.headers, "rows")?;
+                let cols = header_u16(&value.headers, "cols")?;
+                let clients = header_clients(&value.headers)?;
+                Ok(Output::WindowResize { clients, rows, cols })
+            }
+            "snapshot" => {
+                let base_seq = header_u64(&value.headers, "base_seq")?;
+                let rows = header_u16(&value.headers, "rows")?;
+                let cols = header_u16(&value.headers, "cols")?;
+                Ok(Output::Snapshot {
+                    base_seq,
+                    rows,
+                    cols,
+                    body: value.body.to_vec(),
+                })
+            }
+            "exit" => {
+                let code = header_u64(&value.headers, "code")? as u32;
+                let reason = header_str(&value.headers, "reason")?;
+                Ok(Output::Exit { code, reason })
+            }
+            _ => Err(eyre!("unrecognized type")),
+        }
+    }
+}
+
+/// Get current timestamp in ms.
+pub fn timestamp_now() -> u64 {
+    let now = SystemTime::now();
+    now.duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
@@ -0,0 +1,491 @@
+use crate::proto::{Input, Role, timestamp_now};
+use eyre::eyre;
+use portable_pty::{CommandBuilder, PtySize, native_pty_system};
+use s2::batching::{AppendRecordsBatchingOpts, AppendRecordsBatchingStream};
+use s2::client::S2Endpoints;
+use s2::types::{AppendRecord, BasinName, Header, ReadOutput, ReadSessionRequest, ReadStart, StreamPosition};
+use s2::{ClientConfig, StreamClient};
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{error, trace};
+
+/// How often (in records and in wall-clock time, whichever comes first) to
+/// fold the output stream so far into a `snapshot` record.
+pub struct SnapshotOpts {
+    pub every_records: u32,
+    pub every: Duration,
+}
+
+/// Backoff bounds for reconnecting `term_input`/`term_output` after a
+/// transient S2 error. Backoff doubles on each consecutive failure, capped
+/// at `max_backoff`, and resets to `min_backoff` once a stream makes progress
+/// again.
+pub struct ReconnectOpts {
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+/// Tunables for how appends to `term_output` are batched before being sent
+/// over the wire.
+pub struct BatchOpts {
+    pub max_batch_records: u32,
+    pub linger: Duration,
+}
+
+/// S2's per-record metered-size ceiling, minus headroom for headers and
+/// batch framing. A burst of PTY output larger than this is split into
+/// multiple ordered `out` records rather than handed to `AppendRecord::new`
+/// as one oversized record.
+const MAX_RECORD_BYTES: usize = 1024 * 1024 - 4096;
+
+/// S2's per-batch metered-size ceiling. `max_batch_records` alone bounds a
+/// batch by record count, which isn't enough once individual records can
+/// approach `MAX_RECORD_BYTES`: `AppendRecordsBatchingStream` is told about
+/// this ceiling too, so it flushes a batch by accumulated size as well as by
+/// count.
+const MAX_BATCH_BYTES: usize = 1024 * 1024 - 4096;
+
+/// Split a PTY output burst into chunks that each fit under
+/// `MAX_RECORD_BYTES`. Splitting mid-escape-sequence is fine: a `vt100`
+/// parser consuming the chunks back-to-back sees the same byte stream
+/// either way, as long as they're appended in order.
+fn chunk_for_append(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.chunks(MAX_RECORD_BYTES)
+}
+
+/// An append sent to `term_output` whose effect on the server-side `vt100`
+/// screen can only be applied once it's durably acked, so snapshots always
+/// describe state that's actually on the stream. Also what we replay
+/// through a freshly (re)connected append session after a disconnect.
+enum Pending {
+    Out(Vec<u8>),
+    Window { rows: u16, cols: u16 },
+    Snapshot,
+}
+
+/// Encode attached client ids as the comma-separated value of a `clients` header.
+fn join_clients(attached: &BTreeSet<String>) -> String {
+    attached.iter().cloned().collect::<Vec<_>>().join(",")
+}
+
+/// Why the recording loop stopped, so the final record on `term_output`
+/// reflects what actually happened instead of always claiming a crash.
+enum ExitReason {
+    ChildExited { code: u32, reason: String },
+    WriteFailed(eyre::Report),
+}
+
+fn new_client(basin: &str, stream: String) -> eyre::Result<StreamClient> {
+    Ok(StreamClient::new(
+        ClientConfig::new(std::env::var("S2_ACCESS_TOKEN")?)
+            .with_endpoints(S2Endpoints::from_env().map_err(|msg| eyre!(msg))?),
+        BasinName::try_from(basin.to_string())?,
+        stream,
+    ))
+}
+
+/// Open (or re-open) the append session for `term_output`, matching at the
+/// current tail so a write is rejected if the stream has moved past the
+/// position we read. Returns that tail; it does not detect records whose
+/// content is already on the stream, so the caller must skip those itself.
+async fn open_output(
+    basin: &str,
+    output_stream: &str,
+    batch: &BatchOpts,
+) -> eyre::Result<(
+    mpsc::UnboundedSender<AppendRecord>,
+    s2::client::AppendSessionStream<AppendRecordsBatchingStream<UnboundedReceiverStream<AppendRecord>>>,
+    u64,
+)> {
+    let client = new_client(basin, output_stream.to_string())?;
+    let StreamPosition { seq_num, .. } = client.check_tail().await?;
+
+    let (append_tx, append_rx) = mpsc::unbounded_channel();
+    let batching_opts = AppendRecordsBatchingOpts::new()
+        .with_max_batch_records(batch.max_batch_records)
+        .with_max_batch_bytes(MAX_BATCH_BYTES)
+        .with_linger(batch.linger)
+        .with_match_seq_num(Some(seq_num));
+
+    let append = client
+        .append_session(AppendRecordsBatchingStream::new(
+            UnboundedReceiverStream::new(append_rx),
+            batching_opts,
+        ))
+        .await?;
+
+    Ok((append_tx, append, seq_num))
+}
+
+/// Spawn `process` in a PTY, streaming its output to `term_output` while
+/// applying keystrokes and resizes read from `term_input`. Transient S2
+/// errors on either stream trigger a backed-off reconnect rather than
+/// tearing down the recorded process.
+pub async fn run(
+    basin: String,
+    session: String,
+    process: String,
+    snapshots: SnapshotOpts,
+    reconnect: ReconnectOpts,
+    batch: BatchOpts,
+) -> eyre::Result<()> {
+    let pty_system = native_pty_system();
+
+    let pair = pty_system
+        .openpty(PtySize {
+            rows: 32,
+            cols: 72,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .map_err(|e| eyre!(e))?;
+
+    let cmd = CommandBuilder::new(process);
+    let mut child = pair.slave.spawn_command(cmd).map_err(|e| eyre!(e))?;
+
+    // Obtain a reader and writer for the PTY master
+    let reader = pair.master.try_clone_reader().map_err(|e| eyre!(e))?;
+    let mut writer = pair.master.take_writer().map_err(|e| eyre!(e))?;
+
+    // `wait` blocks, so watch for the child's exit from its own task and
+    // surface it as a message rather than polling.
+    let (exit_tx, mut exit_rx) = mpsc::unbounded_channel::<eyre::Result<portable_pty::ExitStatus>>();
+    tokio::task::spawn_blocking(move || {
+        let _ = exit_tx.send(child.wait().map_err(|e| eyre!(e)));
+    });
+
+    let input_stream = format!("sessions/{session}/term_input");
+    let output_stream = format!("sessions/{session}/term_output");
+
+    // Note that we always start from the current tail of the input stream.
+    let mut keystrokes = new_client(&basin, input_stream.clone())?
+        .read_session(ReadSessionRequest::new(ReadStart::TailOffset(0)))
+        .await?;
+
+    let (mut append_tx, mut append, mut next_unacked_seq) = open_output(&basin, &output_stream, &batch).await?;
+
+    // Spawn a task to consume from the PTY reader.
+    // The `read` fn blocks, so this needs to happen in its own task.
+    // Whenever a read finishes, we communicate it via mpsc channel.
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::task::spawn_blocking(move || {
+        let mut reader = reader;
+        let mut buf = [0u8; 1024 * 10];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break, // EOF
+                Ok(n) => {
+                    let data = buf[..n].to_vec();
+                    if tx.send(data).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!(?e, "read error");
+                    break;
+                }
+            }
+        }
+    });
+
+    // Mirrors the reconstructed screen so we can fold it into periodic
+    // `snapshot` records without a reader having to replay the whole stream.
+    let mut screen = vt100::Parser::new(32, 72, 0);
+    let mut pending: VecDeque<Pending> = VecDeque::new();
+    let mut last_acked_out_seq: Option<u64> = None;
+    let mut records_since_snapshot: u32 = 0;
+    let mut last_snapshot_at = Instant::now();
+
+    // Every client id we've seen a `term_input` record from. There's no
+    // explicit detach message, so this is "ever attached" rather than a
+    // live presence set; it's what `out`/`window` records are tagged with.
+    let mut attached_clients: BTreeSet<String> = BTreeSet::new();
+    let mut exit_reason: Option<ExitReason> = None;
+
+    let mut last_processed_input_seq: Option<u64> = None;
+    let mut input_backoff = reconnect.min_backoff;
+    let mut output_backoff = reconnect.min_backoff;
+
+    'outer: loop {
+        tokio::select! {
+
+            // Handle messages from the input stream.
+            input_msg = keystrokes.next() => {
+                match input_msg {
+                    Some(Ok(ReadOutput::Batch(batch))) => {
+                        input_backoff = reconnect.min_backoff;
+                        for record in batch.records {
+                            let seq_num = record.seq_num;
+                            match Input::try_from(record)? {
+                                Input::Keystroke { client, role, data } => {
+                                    attached_clients.insert(client.clone());
+                                    if role != Role::ReadWrite {
+                                        trace!(?client, "dropping keystroke from read-only client");
+                                        last_processed_input_seq = Some(seq_num);
+                                        continue;
+                                    }
+                                    trace!(?client, ?data, "keystroke");
+                                    let write: Result<(), eyre::Report> = (|| {
+                                        writer.write_all(data.as_slice())?;
+                                        writer.flush()?;
+                                        Ok(())
+                                    })();
+                                    if let Err(e) = write {
+                                        error!(?e);
+                                        exit_reason = Some(ExitReason::WriteFailed(e));
+                                        break 'outer;
+                                    }
+                                },
+                                Input::WindowResize { client, role, rows, cols } => {
+                                    attached_clients.insert(client.clone());
+                                    if role != Role::ReadWrite {
+                                        trace!(?client, "dropping resize from read-only client");
+                                        last_processed_input_seq = Some(seq_num);
+                                        continue;
+                                    }
+                                    trace!(?client, ?rows, ?cols, "window resize");
+                                    pair.master.resize(PtySize {
+                                        rows,
+                                        cols,
+                                        pixel_width: 0,
+                                        pixel_height: 0
+                                    }).map_err(|e| eyre!(e))?;
+
+                                    // Record the resize in `term_output` too, so a replay can
+                                    // reconstruct the screen size at the point it changed.
+                                    let record = AppendRecord::new(Vec::new())?
+                                        .with_timestamp(timestamp_now())
+                                        .with_headers([
+                                            Header::new("type", "window"),
+                                            Header::new("rows", rows.to_string()),
+                                            Header::new("cols", cols.to_string()),
+                                            Header::new("clients", join_clients(&attached_clients)),
+                                        ])?;
+                                    append_tx.send(record)?;
+                                    pending.push_back(Pending::Window { rows, cols });
+                                }
+                            }
+                            last_processed_input_seq = Some(seq_num);
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    other => {
+                        if let Some(Err(e)) = other {
+                            error!(?e, "term_input stream error, reconnecting");
+                        } else {
+                            error!("term_input stream ended unexpectedly, reconnecting");
+                        }
+                        tokio::time::sleep(input_backoff).await;
+                        input_backoff = (input_backoff * 2).min(reconnect.max_backoff);
+
+                        let start = match last_processed_input_seq {
+                            Some(seq) => ReadStart::SeqNum(seq + 1),
+                            None => ReadStart::TailOffset(0),
+                        };
+                        keystrokes = new_client(&basin, input_stream.clone())?
+                            .read_session(ReadSessionRequest::new(start))
+                            .await?;
+                    }
+                }
+            }
+
+            // Handle PTY output. A single read can be larger than S2's per-record
+            // limit, so split it into ordered chunks that each fit.
+            Some(msg) = rx.recv() => {
+                let content = String::from_utf8_lossy(&msg);
+                trace!(?content);
+                for chunk in chunk_for_append(&msg) {
+                    let record = match AppendRecord::new(chunk.to_vec()) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            error!(?e, size = chunk.len(), "output chunk failed metered-size check, dropping chunk");
+                            continue;
+                        }
+                    };
+                    let record = match record.with_timestamp(timestamp_now()).with_headers([
+                        Header::new("type", "out"),
+                        Header::new("clients", join_clients(&attached_clients)),
+                    ]) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            error!(?e, size = chunk.len(), "output chunk failed metered-size check, dropping chunk");
+                            continue;
+                        }
+                    };
+                    append_tx.send(record)?;
+                    pending.push_back(Pending::Out(chunk.to_vec()));
+                }
+            }
+
+            // Acknowledgements from appends to the output stream. We only fold an
+            // append's effect into `screen` once it's acked here, so a `snapshot`
+            // built from `screen` always describes durable state.
+            output_ack = append.next() => {
+                match output_ack {
+                    Some(Ok(ack)) => {
+                        trace!(?ack);
+                        output_backoff = reconnect.min_backoff;
+
+                        let mut seq_num = ack.start.seq_num;
+                        while seq_num < ack.end.seq_num {
+                            match pending.pop_front() {
+                                Some(Pending::Out(bytes)) => {
+                                    screen.process(&bytes);
+                                    last_acked_out_seq = Some(seq_num);
+                                    records_since_snapshot += 1;
+                                }
+                                Some(Pending::Window { rows, cols }) => {
+                                    screen.set_size(rows, cols);
+                                    last_acked_out_seq = Some(seq_num);
+                                }
+                                Some(Pending::Snapshot) | None => {}
+                            }
+                            seq_num += 1;
+                        }
+                        next_unacked_seq = ack.end.seq_num;
+
+                        let due = records_since_snapshot >= snapshots.every_records
+                            || last_snapshot_at.elapsed() >= snapshots.every;
+                        if due {
+                            if let Some(base_seq) = last_acked_out_seq {
+                                let (rows, cols) = screen.screen().size();
+                                let record = AppendRecord::new(screen.screen().contents_formatted())?
+                                    .with_timestamp(timestamp_now())
+                                    .with_headers([
+                                        Header::new("type", "snapshot"),
+                                        Header::new("base_seq", base_seq.to_string()),
+                                        Header::new("rows", rows.to_string()),
+                                        Header::new("cols", cols.to_string()),
+                                    ])?;
+                                append_tx.send(record)?;
+                                pending.push_back(Pending::Snapshot);
+                                records_since_snapshot = 0;
+                                last_snapshot_at = Instant::now();
+                            }
+                        }
+                    }
+                    other => {
+                        if let Some(Err(e)) = other {
+                            error!(?e, "term_output append stream error, reconnecting");
+                        } else {
+                            error!("term_output append stream ended unexpectedly, reconnecting");
+                        }
+                        tokio::time::sleep(output_backoff).await;
+                        output_backoff = (output_backoff * 2).min(reconnect.max_backoff);
+
+                        let (new_tx, new_append, durable_seq) = open_output(&basin, &output_stream, &batch).await?;
+                        append_tx = new_tx;
+                        append = new_append;
+
+                        // `durable_seq` reflects everything the server actually has,
+                        // even records whose ack never reached us before the drop.
+                        // Fold those into `screen` as if acked instead of resending
+                        // them, or they'd end up written twice: `with_match_seq_num`
+                        // in `open_output` only guards the position of a *new*
+                        // write, not whether its content already exists further back.
+                        let already_durable = durable_seq.saturating_sub(next_unacked_seq);
+                        for _ in 0..already_durable {
+                            match pending.pop_front() {
+                                Some(Pending::Out(bytes)) => screen.process(&bytes),
+                                Some(Pending::Window { rows, cols }) => screen.set_size(rows, cols),
+                                Some(Pending::Snapshot) | None => {}
+                            }
+                        }
+                        last_acked_out_seq = durable_seq.checked_sub(1);
+                        next_unacked_seq = durable_seq;
+
+                        // Re-send whatever's left, i.e. whatever the server doesn't
+                        // already durably have.
+                        for item in std::mem::take(&mut pending) {
+                            match item {
+                                Pending::Out(bytes) => {
+                                    let record = AppendRecord::new(bytes.clone())?
+                                        .with_timestamp(timestamp_now())
+                                        .with_headers([
+                                            Header::new("type", "out"),
+                                            Header::new("clients", join_clients(&attached_clients)),
+                                        ])?;
+                                    append_tx.send(record)?;
+                                    pending.push_back(Pending::Out(bytes));
+                                }
+                                Pending::Window { rows, cols } => {
+                                    let record = AppendRecord::new(Vec::new())?
+                                        .with_timestamp(timestamp_now())
+                                        .with_headers([
+                                            Header::new("type", "window"),
+                                            Header::new("rows", rows.to_string()),
+                                            Header::new("cols", cols.to_string()),
+                                            Header::new("clients", join_clients(&attached_clients)),
+                                        ])?;
+                                    append_tx.send(record)?;
+                                    pending.push_back(Pending::Window { rows, cols });
+                                }
+                                // Will be regenerated on the next interval; no original
+                                // bytes kept around to resend verbatim.
+                                Pending::Snapshot => {}
+                            }
+                        }
+                    }
+                }
+            }
+
+            // The child process exited on its own; this is the normal way a
+            // session ends, not an error.
+            Some(status) = exit_rx.recv() => {
+                let status = status?;
+                let code = status.exit_code();
+                let reason = if status.success() {
+                    "process exited successfully".to_string()
+                } else {
+                    format!("process exited with code {code}")
+                };
+                trace!(?code, ?reason, "child exited");
+                exit_reason = Some(ExitReason::ChildExited { code, reason });
+                break 'outer;
+            }
+
+            else => {
+                break;
+            }
+        }
+    }
+
+    let final_record = match exit_reason {
+        Some(ExitReason::ChildExited { code, reason }) => AppendRecord::new(Vec::new())?
+            .with_timestamp(timestamp_now())
+            .with_headers([
+                Header::new("type", "exit"),
+                Header::new("code", code.to_string()),
+                Header::new("reason", reason),
+            ])?,
+        Some(ExitReason::WriteFailed(e)) => {
+            AppendRecord::new(format!("\r\n\x1b[31mserver crashed: {e}\x1b[0m\r"))?
+                .with_timestamp(timestamp_now())
+                .with_headers([Header::new("type", "out"), Header::new("clients", join_clients(&attached_clients))])?
+        }
+        None => AppendRecord::new("\r\n\x1b[31mserver crashed :-!\x1b[0m\r")?
+            .with_timestamp(timestamp_now())
+            .with_headers([Header::new("type", "out"), Header::new("clients", join_clients(&attached_clients))])?,
+    };
+
+    // A closed channel here just means the append task already gave up on a
+    // prior error; that's expected during shutdown, not a fresh failure.
+    if let Err(e) = append_tx.send(final_record) {
+        trace!(?e, "output pipeline already closed, dropping final record");
+    }
+    drop(append_tx);
+
+    // Drain any outstanding acks so buffered output is flushed before we return.
+    while let Some(ack) = append.next().await {
+        if let Err(e) = ack {
+            trace!(?e, "append stream closed during shutdown drain");
+            break;
+        }
+    }
+
+    Ok(())
+}
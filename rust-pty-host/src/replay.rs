@@ -0,0 +1,146 @@
+use crate::proto::Output;
+use eyre::eyre;
+use s2::client::S2Endpoints;
+use s2::types::{BasinName, ReadOutput, ReadSessionRequest, ReadStart};
+use s2::{ClientConfig, StreamClient};
+use std::io::Write;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tracing::trace;
+
+/// Default terminal size to assume until the first `window` record (or a
+/// loaded snapshot) tells us otherwise.
+const DEFAULT_ROWS: u16 = 32;
+const DEFAULT_COLS: u16 = 72;
+
+/// Cap on the pause between two records so a long idle gap in the original
+/// session doesn't stall playback indefinitely.
+const MAX_STEP_DELAY: Duration = Duration::from_secs(2);
+
+/// How far back from the tail to scan, in a single bounded read, while
+/// looking for the most recent `snapshot` record.
+const SNAPSHOT_LOOKBACK_RECORDS: u64 = 2000;
+
+/// Replay a recorded session by reconstructing terminal state from
+/// `term_output` and rendering it to the local terminal, paced by the
+/// original record timestamps scaled by `speed`. If `start_seq_num` is
+/// `None`, playback fast-starts from the most recent snapshot near the tail
+/// instead of replaying the whole stream from the beginning.
+pub async fn run(basin: String, session: String, start_seq_num: Option<u64>, speed: f64) -> eyre::Result<()> {
+    if speed <= 0.0 {
+        return Err(eyre!("--speed must be positive"));
+    }
+
+    let output_stream = format!("sessions/{session}/term_output");
+    let client = StreamClient::new(
+        ClientConfig::new(std::env::var("S2_ACCESS_TOKEN")?)
+            .with_endpoints(S2Endpoints::from_env().map_err(|msg| eyre!(msg))?),
+        BasinName::try_from(basin)?,
+        output_stream,
+    );
+
+    let (read_start, mut parser) = match start_seq_num {
+        Some(seq_num) => (
+            ReadStart::SeqNum(seq_num),
+            vt100::Parser::new(DEFAULT_ROWS, DEFAULT_COLS, 0),
+        ),
+        None => match find_latest_snapshot(&client).await? {
+            Some((base_seq, parser)) => (ReadStart::SeqNum(base_seq + 1), parser),
+            None => (ReadStart::SeqNum(0), vt100::Parser::new(DEFAULT_ROWS, DEFAULT_COLS, 0)),
+        },
+    };
+
+    let mut records = client.read_session(ReadSessionRequest::new(read_start)).await?;
+    let mut last_timestamp: Option<u64> = None;
+    let mut stdout = std::io::stdout();
+
+    // Render whatever a loaded snapshot already put on the screen before
+    // waiting on the first record after it.
+    redraw(&mut stdout, &parser)?;
+
+    while let Some(msg) = records.next().await {
+        let msg = msg?;
+        let ReadOutput::Batch(batch) = msg else {
+            continue;
+        };
+
+        for record in batch.records {
+            if let Some(prev) = last_timestamp {
+                let gap = Duration::from_millis(record.timestamp.saturating_sub(prev));
+                let paced = Duration::from_secs_f64(gap.as_secs_f64() / speed).min(MAX_STEP_DELAY);
+                tokio::time::sleep(paced).await;
+            }
+            last_timestamp = Some(record.timestamp);
+
+            match Output::try_from(record)? {
+                Output::Bytes { data, .. } => {
+                    parser.process(&data);
+                }
+                Output::WindowResize { rows, cols, .. } => {
+                    trace!(?rows, ?cols, "replay window resize");
+                    parser.set_size(rows, cols);
+                }
+                // Already durably folded into the stream by the time we'd see
+                // another one live; nothing further to apply.
+                Output::Snapshot { .. } => {}
+                Output::Exit { code, reason } => {
+                    trace!(?code, ?reason, "recorded session exited");
+                    redraw(&mut stdout, &parser)?;
+                    return Ok(());
+                }
+            }
+
+            redraw(&mut stdout, &parser)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Redraw the full screen; simplest way to stay correct across resizes.
+fn redraw(stdout: &mut impl Write, parser: &vt100::Parser) -> eyre::Result<()> {
+    stdout.write_all(b"\x1b[H\x1b[2J")?;
+    stdout.write_all(&parser.screen().contents_formatted())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Find the most recent `snapshot` record near the tail of `term_output` by
+/// reading forward through the last `SNAPSHOT_LOOKBACK_RECORDS` records up to
+/// it, and load it into a fresh parser. Returns `None` if no snapshot lies in
+/// that window (e.g. a short session), in which case the caller should fall
+/// back to replaying from the start of the stream.
+async fn find_latest_snapshot(client: &StreamClient) -> eyre::Result<Option<(u64, vt100::Parser)>> {
+    let tail = client.check_tail().await?;
+    if tail.seq_num == 0 {
+        return Ok(None);
+    }
+    let window_start = tail.seq_num.saturating_sub(SNAPSHOT_LOOKBACK_RECORDS);
+
+    let mut records = client
+        .read_session(ReadSessionRequest::new(ReadStart::SeqNum(window_start)))
+        .await?;
+
+    let mut latest: Option<(u64, u16, u16, Vec<u8>)> = None;
+    'scan: while let Some(msg) = records.next().await {
+        let msg = msg?;
+        let ReadOutput::Batch(batch) = msg else {
+            continue;
+        };
+        for record in batch.records {
+            let reached_tail = record.seq_num + 1 >= tail.seq_num;
+            if let Output::Snapshot { base_seq, rows, cols, body } = Output::try_from(record)? {
+                latest = Some((base_seq, rows, cols, body));
+            }
+            if reached_tail {
+                break 'scan;
+            }
+        }
+    }
+
+    Ok(latest.map(|(base_seq, rows, cols, body)| {
+        let mut parser = vt100::Parser::new(rows, cols, 0);
+        parser.process(&body);
+        (base_seq, parser)
+    }))
+}